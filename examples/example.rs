@@ -3,21 +3,21 @@ use std::{
     net::TcpStream,
 };
 
-use tinymqtt::MqttClient;
+use tinymqtt::{MqttClient, ProtocolVersion};
 
 fn main() {
-    let mut client: MqttClient<1024> = MqttClient::new();
+    let mut client: MqttClient<1024> = MqttClient::new(ProtocolVersion::V5);
 
     let mut stream = TcpStream::connect(std::env::var("TINYMQTT_HOST").unwrap()).unwrap();
     stream
-        .write_all(client.connect("12345", None).unwrap())
+        .write_all(client.connect("12345", 0, None, None).unwrap())
         .unwrap();
     stream.flush().unwrap();
 
     let mut rx_bytes = [0; 1024];
     let len = stream.read(&mut rx_bytes).unwrap();
     client
-        .receive_packet(&rx_bytes[..len], |client, topic, data| {
+        .receive_packet(&rx_bytes[..len], |client, topic, data, _properties| {
             println!("Received: {:?} {:?}", topic, std::str::from_utf8(data));
         })
         .unwrap();
@@ -33,7 +33,7 @@ fn main() {
     loop {
         let len = stream.read(&mut rx_bytes).unwrap();
         client
-            .receive_packet(&rx_bytes[..len], |client, topic, data| {
+            .feed(&rx_bytes[..len], |client, topic, data, _properties| {
                 println!("Received: {:?} {:?}", topic, std::str::from_utf8(data));
             })
             .unwrap();
@@ -11,27 +11,56 @@ pub mod reader_writer;
 
 pub type MqttResult<T> = Result<T, MqttError>;
 
-pub struct MqttClient<const N: usize> {
+type PublishCallback<'a, T> = dyn FnMut(&mut T, &str, &[u8], MqttProperties) + 'a;
+
+pub struct MqttClient<const N: usize, const K: usize = 8> {
     state: MqttState,
     packet_counter: PacketIdCounter,
 
     construct_buffer: [u8; N],
     message_buffer: [u8; N],
+
+    version: ProtocolVersion,
+    keep_alive_secs: u16,
+    ping_pending: bool,
+
+    // Packet ids we're still waiting to be acknowledged
+    pending_qos1: PendingIds<K>,
+    pending_qos2_rec: PendingIds<K>,
+    pending_qos2_comp: PendingIds<K>,
+
+    // Acks generated while handling an incoming packet, waiting to be sent out
+    outgoing_acks: AckQueue<K>,
+
+    // Partially received packet, buffered by `feed` until `remaining_length` bytes arrive
+    frame_buffer: [u8; N],
+    frame_len: usize,
 }
 
-impl<const N: usize> MqttClient<N> {
-    pub fn new() -> Self {
+impl<const N: usize, const K: usize> MqttClient<N, K> {
+    pub fn new(version: ProtocolVersion) -> Self {
         Self {
             state: MqttState::Disconnected,
             packet_counter: PacketIdCounter::new(),
             construct_buffer: [0; N],
             message_buffer: [0; N],
+            version,
+            keep_alive_secs: 0,
+            ping_pending: false,
+            pending_qos1: PendingIds::new(),
+            pending_qos2_rec: PendingIds::new(),
+            pending_qos2_comp: PendingIds::new(),
+            outgoing_acks: AckQueue::new(),
+            frame_buffer: [0; N],
+            frame_len: 0,
         }
     }
 
     pub fn connect(
         &mut self,
         client_id: &str,
+        keep_alive_secs: u16,
+        will: Option<Will>,
         username_password: Option<(&str, &str)>,
     ) -> MqttResult<&[u8]> {
         let mut writer = MqttMessageWriter::new(&mut self.construct_buffer);
@@ -39,60 +68,166 @@ impl<const N: usize> MqttClient<N> {
         // Connect flags
         let mut flags = Flags::zero();
         flags.set(1); // clean start
+        if let Some(will) = &will {
+            flags.set(2); // will flag
+            let qos = will.qos as u8;
+            if qos & 0b01 != 0 {
+                flags.set(3); // will QoS bit 0
+            }
+            if qos & 0b10 != 0 {
+                flags.set(4); // will QoS bit 1
+            }
+            if will.retain {
+                flags.set(5); // will retain
+            }
+        }
         if username_password.is_some() {
             flags.set(6).set(7); // user name, password
         }
 
+        let is_v5 = self.version == ProtocolVersion::V5;
+
         // variable header
-        writer.write_string("MQTT");
-        writer.write_u8(0x05); // Protocol version
-        writer.write_flags(flags); // Connect flags
-        writer.write_u16(0); // Keep alive turned off
-        writer.write_u8(0); // No properties
+        writer.write_string("MQTT")?;
+        writer.write_u8(match self.version {
+            ProtocolVersion::V4 => 0x04,
+            ProtocolVersion::V5 => 0x05,
+        })?; // Protocol version
+        writer.write_flags(flags)?; // Connect flags
+        writer.write_u16(keep_alive_secs)?; // Keep alive
+        if is_v5 {
+            writer.write_u8(0)?; // No properties
+        }
 
         // payload
-        writer.write_string(client_id);
+        writer.write_string(client_id)?;
+
+        if let Some(will) = &will {
+            if is_v5 {
+                writer.write_u8(0)?; // No will properties
+            }
+            writer.write_string(will.topic)?;
+            writer.write_bytes(will.payload)?;
+        }
 
         if let Some((username, password)) = username_password {
-            writer.write_string(username);
-            writer.write_string(password);
+            writer.write_string(username)?;
+            writer.write_string(password)?;
         }
 
         self.state = MqttState::Connecting;
+        self.keep_alive_secs = keep_alive_secs;
 
         let len = writer.len();
         self.write_packet(ControlPacketType::CONNECT, Flags::zero(), len)
     }
 
+    /// Emits a `PINGREQ`, which must be sent whenever [`Self::needs_ping`] says the
+    /// keep-alive interval has elapsed without any other packet being sent.
+    pub fn ping(&mut self) -> MqttResult<&[u8]> {
+        self.assert_state(MqttState::Connected)?;
+        self.ping_pending = true;
+        self.write_packet(ControlPacketType::PINGREQ, Flags::zero(), 0)
+    }
+
+    /// Whether the caller's event loop should call [`Self::ping`], given the number of
+    /// seconds elapsed since a packet was last sent to the broker. Since this crate is
+    /// `no_std` and has no clock of its own, the elapsed time is tracked by the caller.
+    /// Always returns `false` if keep-alive was disabled (`keep_alive_secs == 0`) in
+    /// [`Self::connect`].
+    pub fn needs_ping(&self, elapsed_since_last_send_secs: u32) -> bool {
+        self.keep_alive_secs != 0 && elapsed_since_last_send_secs >= self.keep_alive_secs as u32
+    }
+
+    /// Whether a `PINGREQ` sent via [`Self::ping`] has gone unanswered for at least the
+    /// keep-alive interval, meaning the broker should be considered unreachable and the
+    /// connection dead. `elapsed_since_ping_secs` is the time elapsed since that `ping()`.
+    pub fn ping_timed_out(&self, elapsed_since_ping_secs: u32) -> bool {
+        self.ping_pending
+            && self.keep_alive_secs != 0
+            && elapsed_since_ping_secs >= self.keep_alive_secs as u32
+    }
+
     pub fn publish(&mut self, topic: &str, payload: &[u8]) -> Result<&[u8], MqttError> {
+        self.publish_qos(topic, payload, MqttQoS::AtMostOnce, MqttProperties::default())
+    }
+
+    /// Like [`Self::publish`], but for QoS 1 and 2 allocates a packet identifier and tracks
+    /// it until the broker's acknowledgement handshake for that QoS completes (drain the
+    /// handshake packets this produces via [`Self::next_outgoing`]), and attaches
+    /// `properties` to the PUBLISH on [`ProtocolVersion::V5`] connections.
+    pub fn publish_qos(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: MqttQoS,
+        properties: MqttProperties,
+    ) -> Result<&[u8], MqttError> {
         self.assert_state(MqttState::Connected)?; // Check if the client is connected
 
+        let packet_id = if qos == MqttQoS::AtMostOnce {
+            0
+        } else {
+            self.packet_counter.next()
+        };
+
+        let is_v5 = self.version == ProtocolVersion::V5;
         let mut writer = MqttMessageWriter::new(&mut self.construct_buffer);
 
         // variable header
-        writer.write_string(topic);
-        writer.write_u16(0); // Packet identifier
-        writer.write_u8(0); // No properties
+        writer.write_string(topic)?;
+        if qos != MqttQoS::AtMostOnce {
+            // The packet identifier field is only present when a QoS > 0 requires an ack
+            writer.write_u16(packet_id)?;
+        }
+        if is_v5 {
+            properties.write(&mut writer)?;
+        }
 
         // payload
-        writer.write_bytes_raw(payload);
+        writer.write_bytes_raw(payload)?;
+
+        let flags = match qos {
+            MqttQoS::AtMostOnce => Flags::zero(),
+            MqttQoS::AtLeastOnce => Flags::new(0b0010),
+            MqttQoS::ExactlyOnce => Flags::new(0b0100),
+        };
+
+        match qos {
+            MqttQoS::AtMostOnce => {}
+            MqttQoS::AtLeastOnce => self.pending_qos1.insert(packet_id)?,
+            MqttQoS::ExactlyOnce => self.pending_qos2_rec.insert(packet_id)?,
+        }
 
         let len = writer.len();
-        self.write_packet(ControlPacketType::PUBLISH, Flags::zero(), len)
+        self.write_packet(ControlPacketType::PUBLISH, flags, len)
     }
 
     pub fn subscribe(&mut self, topic_filter: &str) -> Result<&[u8], MqttError> {
+        self.subscribe_qos(topic_filter, MqttQoS::AtMostOnce)
+    }
+
+    /// Like [`Self::subscribe`], but requests `max_qos` as the maximum QoS for the
+    /// subscription instead of always requesting QoS 0.
+    pub fn subscribe_qos(
+        &mut self,
+        topic_filter: &str,
+        max_qos: MqttQoS,
+    ) -> Result<&[u8], MqttError> {
         self.assert_state(MqttState::Connected)?; // Check if the client is connected
 
+        let is_v5 = self.version == ProtocolVersion::V5;
         let mut writer = MqttMessageWriter::new(&mut self.construct_buffer);
 
         // variable header
-        writer.write_u16(self.packet_counter.next()); // Packet identifier
-        writer.write_u8(0); // No properties
+        writer.write_u16(self.packet_counter.next())?; // Packet identifier
+        if is_v5 {
+            writer.write_u8(0)?; // No properties
+        }
 
         // payload
-        writer.write_string(topic_filter);
-        writer.write_flags(Flags::zero()); // Subscription Options (with maximum QoS 0)
+        writer.write_string(topic_filter)?;
+        writer.write_flags(Flags::new(max_qos as u8))?; // Subscription Options (with maximum QoS)
 
         let len = writer.len();
         self.write_packet(ControlPacketType::SUBSCRIBE, Flags::new(0b0010), len)
@@ -101,70 +236,241 @@ impl<const N: usize> MqttClient<N> {
     pub fn unsubscribe(&mut self, topic_filter: &str) -> Result<&[u8], MqttError> {
         self.assert_state(MqttState::Connected)?; // Check if the client is connected
 
+        let is_v5 = self.version == ProtocolVersion::V5;
         let mut writer = MqttMessageWriter::new(&mut self.construct_buffer);
 
         // variable header
-        writer.write_u16(self.packet_counter.next()); // Packet identifier
-        writer.write_u8(0); // No properties
+        writer.write_u16(self.packet_counter.next())?; // Packet identifier
+        if is_v5 {
+            writer.write_u8(0)?; // No properties
+        }
 
         // payload
-        writer.write_string(topic_filter);
+        writer.write_string(topic_filter)?;
 
         let len = writer.len();
         self.write_packet(ControlPacketType::UNSUBSCRIBE, Flags::new(0b0010), len)
     }
 
+    /// Parses `packet` as one or more complete, back-to-back MQTT packets and handles each
+    /// in turn. Every packet's `remaining_length` must fit inside `packet` - a packet split
+    /// across multiple reads (as happens with real TCP) will fail to parse. Prefer
+    /// [`Self::feed`] when reading from a stream, since it reassembles split packets itself.
     pub fn receive_packet(
         &mut self,
         packet: &[u8],
-        mut on_publish_rec: impl FnMut(&mut Self, &str, &[u8]) -> (),
+        mut on_publish_rec: impl FnMut(&mut Self, &str, &[u8], MqttProperties) -> (),
     ) -> Result<MqttState, MqttError> {
         let mut reader = MqttMessageReader::new(packet);
 
         while reader.remaining() > 0 {
             // Parse fixed header
-            let fixed_header = reader.read_u8();
-            let ty = ControlPacketType::from_u8(fixed_header >> 4).unwrap();
-            let _fixed_header_flags = Flags::new(fixed_header & 0x0F);
-            let remaining_length = reader.read_variable_int() as usize;
+            let fixed_header = reader.read_u8()?;
+            let ty = ControlPacketType::from_u8(fixed_header >> 4).ok_or(MqttError::InvalidPacket)?;
+            let fixed_header_flags = Flags::new(fixed_header & 0x0F);
+            let remaining_length = reader.read_variable_int()? as usize;
             reader.mark(); // Remember start of packet content so we can skip it later
 
-            match ty {
-                ControlPacketType::CONNACK => {
-                    let _connect_ack = reader.read_u8();
-                    let reason_code = reader.read_u8();
-                    if reason_code != 0 {
-                        self.state = MqttState::Disconnected;
-                        return Err(MqttError::ConnectionRefused);
-                    }
-                    self.state = MqttState::Connected;
-                }
-                ControlPacketType::SUBACK => {
-                    // Nothing to do here
-                }
-                ControlPacketType::UNSUBACK => {
-                    // Nothing to do here
-                }
-                ControlPacketType::PUBLISH => {
-                    let topic = reader.read_string();
-                    let property_length = reader.read_variable_int() as usize;
-                    reader.skip(property_length); // We don't care about properties
-                    let payload_length = remaining_length - reader.distance_from_mark();
-                    let payload = reader.read_bytes_raw(payload_length);
-                    on_publish_rec(self, topic, payload);
+            self.dispatch_packet(
+                ty,
+                fixed_header_flags,
+                remaining_length,
+                &mut reader,
+                &mut on_publish_rec,
+            )?;
+            reader.skip_to(remaining_length);
+        }
+
+        Ok(self.state)
+    }
+
+    /// Feeds in whatever bytes `TcpStream::read` (or equivalent) just returned, reassembling
+    /// MQTT packets that arrive split across multiple reads or coalesced into one. Unlike
+    /// [`Self::receive_packet`], `bytes` may start or end mid-packet; the incomplete
+    /// remainder is buffered internally and completed by a later `feed` call. Every packet
+    /// that becomes complete during this call is dispatched before `feed` returns.
+    pub fn feed(
+        &mut self,
+        mut bytes: &[u8],
+        mut on_publish_rec: impl FnMut(&mut Self, &str, &[u8], MqttProperties),
+    ) -> MqttResult<MqttState> {
+        loop {
+            // Top up the in-progress frame with as much of the new input as fits
+            let available = N - self.frame_len;
+            let take = bytes.len().min(available);
+            self.frame_buffer[self.frame_len..self.frame_len + take].copy_from_slice(&bytes[..take]);
+            self.frame_len += take;
+            bytes = &bytes[take..];
+
+            let Some((ty, fixed_header_flags, remaining_length, header_len)) =
+                Self::parse_fixed_header(&self.frame_buffer[..self.frame_len])?
+            else {
+                return Ok(self.state); // fixed header isn't fully buffered yet
+            };
+
+            let frame_len = header_len + remaining_length;
+            if frame_len > N {
+                return Err(MqttError::BufferFull);
+            }
+            if self.frame_len < frame_len {
+                if bytes.is_empty() {
+                    return Ok(self.state); // wait for the rest of this packet
                 }
-                ControlPacketType::DISCONNECT => {
+                continue; // more input is available; top up the frame and check again
+            }
+
+            // Copy the complete frame onto the stack so it can be read independently of
+            // `self` while `dispatch_packet` also needs a mutable borrow of `self`.
+            let mut frame = [0u8; N];
+            frame[..frame_len].copy_from_slice(&self.frame_buffer[..frame_len]);
+            let mut reader = MqttMessageReader::new(&frame[..frame_len]);
+            reader.skip(header_len);
+            reader.mark();
+            self.dispatch_packet(
+                ty,
+                fixed_header_flags,
+                remaining_length,
+                &mut reader,
+                &mut on_publish_rec,
+            )?;
+
+            // Slide a coalesced next packet (or the start of a partial one) to the front
+            self.frame_buffer.copy_within(frame_len..self.frame_len, 0);
+            self.frame_len -= frame_len;
+
+            if bytes.is_empty() && self.frame_len == 0 {
+                return Ok(self.state);
+            }
+        }
+    }
+
+    /// Parses just the fixed header (packet type, flags, and `remaining_length`) out of
+    /// whatever bytes of a packet are currently available, returning `Ok(None)` rather than
+    /// an error if `buffer` doesn't yet hold the whole header. Returns the decoded header
+    /// plus the number of bytes it occupied.
+    fn parse_fixed_header(
+        buffer: &[u8],
+    ) -> MqttResult<Option<(ControlPacketType, Flags, usize, usize)>> {
+        let Some(&fixed_header) = buffer.first() else {
+            return Ok(None);
+        };
+        let ty = ControlPacketType::from_u8(fixed_header >> 4).ok_or(MqttError::InvalidPacket)?;
+        let fixed_header_flags = Flags::new(fixed_header & 0x0F);
+
+        let mut remaining_length: u32 = 0;
+        let mut shift = 0;
+        for i in 0..4 {
+            let Some(&byte) = buffer.get(1 + i) else {
+                return Ok(None); // haven't received the rest of the length field yet
+            };
+            remaining_length |= ((byte & 0x7F) as u32) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(Some((
+                    ty,
+                    fixed_header_flags,
+                    remaining_length as usize,
+                    1 + i + 1,
+                )));
+            }
+            shift += 7;
+        }
+        Err(MqttError::MalformedPacket) // continuation bit set on the fourth length byte
+    }
+
+    fn dispatch_packet(
+        &mut self,
+        ty: ControlPacketType,
+        fixed_header_flags: Flags,
+        remaining_length: usize,
+        reader: &mut MqttMessageReader,
+        on_publish_rec: &mut PublishCallback<Self>,
+    ) -> MqttResult<()> {
+        match ty {
+            ControlPacketType::CONNACK => {
+                let _connect_ack = reader.read_u8()?;
+                let reason_code = reader.read_u8()?;
+                if reason_code != 0 {
                     self.state = MqttState::Disconnected;
-                    return Err(MqttError::Disconnected);
+                    return Err(MqttError::ConnectionRefused);
                 }
-                _ => {
-                    return Err(MqttError::InvalidPacket);
+                self.state = MqttState::Connected;
+            }
+            ControlPacketType::SUBACK => {
+                // Nothing to do here
+            }
+            ControlPacketType::UNSUBACK => {
+                // Nothing to do here
+            }
+            ControlPacketType::PUBLISH => {
+                // Bits 1-2 of the fixed header flags carry the QoS of this PUBLISH
+                let qos = (fixed_header_flags.value >> 1) & 0b11;
+                let topic = reader.read_string()?;
+                let packet_id = if qos > 0 { reader.read_u16()? } else { 0 };
+                let properties = if self.version == ProtocolVersion::V5 {
+                    MqttProperties::read(reader)?
+                } else {
+                    MqttProperties::default()
+                };
+                let payload_length = remaining_length.saturating_sub(reader.distance_from_mark());
+                let payload = reader.read_bytes_raw(payload_length)?;
+                on_publish_rec(self, topic, payload, properties);
+                match qos {
+                    1 => self.queue_ack(ControlPacketType::PUBACK, Flags::zero(), packet_id)?,
+                    2 => self.queue_ack(ControlPacketType::PUBREC, Flags::zero(), packet_id)?,
+                    _ => {}
                 }
             }
-            reader.skip_to(remaining_length);
+            ControlPacketType::PUBACK => {
+                let packet_id = reader.read_u16()?;
+                self.pending_qos1.remove(packet_id);
+            }
+            ControlPacketType::PUBREC => {
+                let packet_id = reader.read_u16()?;
+                self.pending_qos2_rec.remove(packet_id);
+                self.pending_qos2_comp.insert(packet_id)?;
+                self.queue_ack(ControlPacketType::PUBREL, Flags::new(0b0010), packet_id)?;
+            }
+            ControlPacketType::PUBREL => {
+                let packet_id = reader.read_u16()?;
+                self.queue_ack(ControlPacketType::PUBCOMP, Flags::zero(), packet_id)?;
+            }
+            ControlPacketType::PUBCOMP => {
+                let packet_id = reader.read_u16()?;
+                self.pending_qos2_comp.remove(packet_id);
+            }
+            ControlPacketType::PINGRESP => {
+                self.ping_pending = false;
+            }
+            ControlPacketType::DISCONNECT => {
+                self.state = MqttState::Disconnected;
+                return Err(MqttError::Disconnected);
+            }
+            _ => {
+                return Err(MqttError::InvalidPacket);
+            }
         }
+        Ok(())
+    }
 
-        Ok(self.state)
+    /// Drains the acknowledgement packets (`PUBACK`, `PUBREC`, `PUBREL`, `PUBCOMP`) that
+    /// [`Self::receive_packet`] or [`Self::feed`] generated while processing incoming QoS 1/2
+    /// publishes and the broker's `PUBREC`s for QoS 2 publishes sent via [`Self::publish_qos`].
+    /// Call this after every `receive_packet` or `feed` until it returns `None` and send
+    /// everything it hands back.
+    pub fn next_outgoing(&mut self) -> Option<&[u8]> {
+        let packet = self.outgoing_acks.pop()?;
+        self.message_buffer[..packet.len()].copy_from_slice(&packet);
+        Some(&self.message_buffer[..packet.len()])
+    }
+
+    fn queue_ack(&mut self, ty: ControlPacketType, flags: Flags, packet_id: u16) -> MqttResult<()> {
+        let packet = [
+            (ty as u8) << 4 | flags.value,
+            2, // remaining length: a packet id is always 2 bytes
+            (packet_id >> 8) as u8,
+            packet_id as u8,
+        ];
+        self.outgoing_acks.push(packet)
     }
 
     #[inline(always)]
@@ -184,14 +490,23 @@ impl<const N: usize> MqttClient<N> {
         payload_len: usize,
     ) -> MqttResult<&[u8]> {
         let mut writer = MqttMessageWriter::new(&mut self.message_buffer);
-        writer.write_u8((ty as u8) << 4 | flags.value);
-        writer.write_variable_int(payload_len as u32);
-        writer.write_bytes_raw(&self.construct_buffer[..payload_len]);
+        writer.write_u8((ty as u8) << 4 | flags.value)?;
+        writer.write_variable_int(payload_len as u32)?;
+        writer.write_bytes_raw(&self.construct_buffer[..payload_len])?;
         let len = writer.len();
         Ok(&self.message_buffer[..len])
     }
 }
 
+/// Selects the wire format `MqttClient` speaks. `V4` is MQTT 3.1.1, which has no properties
+/// fields anywhere in the protocol; `V5` is MQTT 5, which adds a properties section to
+/// CONNECT, PUBLISH, SUBSCRIBE, UNSUBSCRIBE and the will.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    V4,
+    V5,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MqttState {
     Disconnected,
@@ -205,13 +520,27 @@ pub enum MqttError {
     InvalidPacket,
     ConnectionRefused,
     Disconnected,
+    TooManyPendingAcks,
+    BufferFull,
+    MalformedPacket,
+    InvalidUtf8,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MqttQoS {
-    AtMostOnce,
-    AtLeastOnce,
-    ExactlyOnce,
+    AtMostOnce = 0,
+    AtLeastOnce = 1,
+    ExactlyOnce = 2,
+}
+
+/// A Last Will and Testament announced to the broker in [`MqttClient::connect`]. If the
+/// client disconnects ungracefully, the broker publishes `payload` to `topic` on its behalf.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Will<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub qos: MqttQoS,
+    pub retain: bool,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive, ToPrimitive)]
@@ -225,6 +554,188 @@ pub enum MqttProperty {
     ReasonString = 0x1F,
 }
 
+/// MQTT 5 properties attached to a PUBLISH. Pass one to [`MqttClient::publish_qos`] to set
+/// the properties the broker sees on the wire; [`MqttClient::receive_packet`] hands one of
+/// these back to `on_publish_rec` with whatever properties the incoming PUBLISH carried.
+/// Has no effect and is always empty on [`ProtocolVersion::V4`], which has no properties.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MqttProperties<'a> {
+    pub payload_format_indicator: Option<u8>,
+    pub message_expiry_interval: Option<u32>,
+    pub content_type: Option<&'a str>,
+    pub response_topic: Option<&'a str>,
+    pub correlation_data: Option<&'a [u8]>,
+}
+
+impl<'a> MqttProperties<'a> {
+    fn encoded_len(&self) -> usize {
+        let mut len = 0;
+        if self.payload_format_indicator.is_some() {
+            len += 1 + 1; // identifier + u8
+        }
+        if self.message_expiry_interval.is_some() {
+            len += 1 + 4; // identifier + u32
+        }
+        if let Some(value) = self.content_type {
+            len += 1 + 2 + value.len(); // identifier + length-prefixed string
+        }
+        if let Some(value) = self.response_topic {
+            len += 1 + 2 + value.len();
+        }
+        if let Some(value) = self.correlation_data {
+            len += 1 + 2 + value.len(); // identifier + length-prefixed binary data
+        }
+        len
+    }
+
+    fn write(&self, writer: &mut MqttMessageWriter) -> MqttResult<()> {
+        writer.write_variable_int(self.encoded_len() as u32)?;
+        if let Some(value) = self.payload_format_indicator {
+            writer.write_u8(MqttProperty::PayloadFormatIndicator as u8)?;
+            writer.write_u8(value)?;
+        }
+        if let Some(value) = self.message_expiry_interval {
+            writer.write_u8(MqttProperty::MessageExpiryInterval as u8)?;
+            writer.write_u32(value)?;
+        }
+        if let Some(value) = self.content_type {
+            writer.write_u8(MqttProperty::ContentType as u8)?;
+            writer.write_string(value)?;
+        }
+        if let Some(value) = self.response_topic {
+            writer.write_u8(MqttProperty::ResponseTopic as u8)?;
+            writer.write_string(value)?;
+        }
+        if let Some(value) = self.correlation_data {
+            writer.write_u8(MqttProperty::CorrelationData as u8)?;
+            writer.write_bytes(value)?;
+        }
+        Ok(())
+    }
+
+    fn read(reader: &mut MqttMessageReader<'a>) -> MqttResult<Self> {
+        let property_length = reader.read_variable_int()? as usize;
+        let end = reader.position() + property_length;
+        let mut properties = Self::default();
+        while reader.position() < end {
+            let identifier = reader.read_u8()?;
+            match MqttProperty::from_u8(identifier) {
+                Some(MqttProperty::PayloadFormatIndicator) => {
+                    properties.payload_format_indicator = Some(reader.read_u8()?)
+                }
+                Some(MqttProperty::MessageExpiryInterval) => {
+                    properties.message_expiry_interval = Some(reader.read_u32()?)
+                }
+                Some(MqttProperty::ContentType) => {
+                    properties.content_type = Some(reader.read_string()?)
+                }
+                Some(MqttProperty::ResponseTopic) => {
+                    properties.response_topic = Some(reader.read_string()?)
+                }
+                Some(MqttProperty::CorrelationData) => {
+                    properties.correlation_data = Some(reader.read_bytes()?)
+                }
+                // Recognized, but not yet surfaced to callers; consume the value so parsing
+                // can continue with whatever properties follow it.
+                Some(MqttProperty::SubscriptionIdentifier) => {
+                    reader.read_variable_int()?;
+                }
+                Some(MqttProperty::ReasonString) => {
+                    reader.read_string()?;
+                }
+                // Unrecognized property identifier; its type (and thus length) is unknown,
+                // so we can't safely keep parsing the rest of the property list. Skip
+                // straight to `end` so the payload that follows isn't misread as leftover
+                // property bytes.
+                None => {
+                    reader.skip(end - reader.position());
+                    break;
+                }
+            }
+            // A property's value must not reach past the length the broker declared;
+            // otherwise we'd silently read into what should be the next property or payload.
+            if reader.position() > end {
+                return Err(MqttError::MalformedPacket);
+            }
+        }
+        Ok(properties)
+    }
+}
+
+// Packet ids currently awaiting an acknowledgement, kept in a fixed-size array so the
+// client stays allocation-free. Packet ids are never 0, so 0 doubles as the empty marker.
+struct PendingIds<const K: usize> {
+    ids: [u16; K],
+}
+
+impl<const K: usize> PendingIds<K> {
+    fn new() -> Self {
+        Self { ids: [0; K] }
+    }
+
+    fn insert(&mut self, id: u16) -> MqttResult<()> {
+        if self.ids.contains(&id) {
+            return Ok(());
+        }
+        for slot in self.ids.iter_mut() {
+            if *slot == 0 {
+                *slot = id;
+                return Ok(());
+            }
+        }
+        Err(MqttError::TooManyPendingAcks)
+    }
+
+    fn remove(&mut self, id: u16) -> bool {
+        for slot in self.ids.iter_mut() {
+            if *slot == id {
+                *slot = 0;
+                return true;
+            }
+        }
+        false
+    }
+}
+
+// Ack packets (PUBACK/PUBREC/PUBREL/PUBCOMP) generated while handling an incoming packet,
+// waiting to be drained via `MqttClient::next_outgoing`. Every one of these packets is
+// exactly 4 bytes, so a ring buffer of fixed-size arrays avoids any allocation.
+struct AckQueue<const K: usize> {
+    packets: [[u8; 4]; K],
+    head: usize,
+    len: usize,
+}
+
+impl<const K: usize> AckQueue<K> {
+    fn new() -> Self {
+        Self {
+            packets: [[0; 4]; K],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, packet: [u8; 4]) -> MqttResult<()> {
+        if self.len == K {
+            return Err(MqttError::TooManyPendingAcks);
+        }
+        let tail = (self.head + self.len) % K;
+        self.packets[tail] = packet;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<[u8; 4]> {
+        if self.len == 0 {
+            return None;
+        }
+        let packet = self.packets[self.head];
+        self.head = (self.head + 1) % K;
+        self.len -= 1;
+        Some(packet)
+    }
+}
+
 struct PacketIdCounter {
     counter: u16,
 }
@@ -259,3 +770,293 @@ pub enum ControlPacketType {
     DISCONNECT = 14,
     AUTH = 15,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connected_client() -> MqttClient<256> {
+        let mut client = MqttClient::new(ProtocolVersion::V4);
+        client.state = MqttState::Connected;
+        client
+    }
+
+    #[test]
+    fn connect_encodes_will_flags_and_payload_order() {
+        let mut client: MqttClient<256> = MqttClient::new(ProtocolVersion::V4);
+        let will = Will {
+            topic: "w",
+            payload: b"bye",
+            qos: MqttQoS::AtLeastOnce,
+            retain: true,
+        };
+        let packet = client.connect("c", 0, Some(will), None).unwrap();
+        assert_eq!(
+            packet,
+            &[
+                0x10, 0x15, // CONNECT, remaining length 21
+                0x00, 0x04, b'M', b'Q', b'T', b'T', // protocol name
+                0x04, // protocol level (V4)
+                0x2E, // connect flags: clean start | will | will QoS 1 | will retain
+                0x00, 0x00, // keep alive
+                0x00, 0x01, b'c', // client id
+                0x00, 0x01, b'w', // will topic
+                0x00, 0x03, b'b', b'y', b'e', // will payload
+            ][..]
+        );
+    }
+
+    #[test]
+    fn needs_ping_respects_keep_alive_threshold() {
+        let mut client = connected_client();
+        assert!(!client.needs_ping(1000)); // keep-alive disabled (0) by default
+
+        client.keep_alive_secs = 10;
+        assert!(!client.needs_ping(9));
+        assert!(client.needs_ping(10)); // exactly at the threshold
+        assert!(client.needs_ping(11));
+    }
+
+    #[test]
+    fn ping_sets_pending_and_ping_timed_out_respects_threshold() {
+        let mut client = connected_client();
+        client.keep_alive_secs = 10;
+        assert!(!client.ping_timed_out(100)); // no ping sent yet
+
+        client.ping().unwrap();
+        assert!(!client.ping_timed_out(9));
+        assert!(client.ping_timed_out(10)); // exactly at the threshold
+        assert!(client.ping_timed_out(11));
+    }
+
+    #[test]
+    fn ping_timed_out_is_false_when_keep_alive_disabled() {
+        let mut client = connected_client();
+        client.ping().unwrap();
+        assert!(!client.ping_timed_out(u32::MAX));
+    }
+
+    #[test]
+    fn pingresp_clears_ping_pending() {
+        let mut client = connected_client();
+        client.keep_alive_secs = 10;
+        client.ping().unwrap();
+        assert!(client.ping_pending);
+
+        let pingresp = [0xD0, 0x00];
+        client.receive_packet(&pingresp, |_, _, _, _| {}).unwrap();
+        assert!(!client.ping_pending);
+    }
+
+    #[test]
+    fn connect_omits_properties_byte_on_v4_but_not_v5() {
+        let mut v4 = MqttClient::<256>::new(ProtocolVersion::V4);
+        let v4_packet = v4.connect("c", 0, None, None).unwrap();
+        assert_eq!(v4_packet[8], 0x04); // protocol level
+        assert_eq!(v4_packet.len(), 15); // no properties-length byte in the variable header
+
+        let mut v5 = MqttClient::<256>::new(ProtocolVersion::V5);
+        let v5_packet = v5.connect("c", 0, None, None).unwrap();
+        assert_eq!(v5_packet[8], 0x05); // protocol level
+        assert_eq!(v5_packet.len(), v4_packet.len() + 1); // one extra properties-length byte
+    }
+
+    #[test]
+    fn publish_omits_properties_byte_on_v4() {
+        let mut client = connected_client(); // V4
+        let packet = client.publish("t", b"x").unwrap();
+        // fixed header(2) + topic "t"(3) + payload "x"(1), no packet id, no properties byte
+        assert_eq!(packet.len(), 6);
+    }
+
+    #[test]
+    fn subscribe_omits_properties_byte_on_v4() {
+        let mut client = connected_client(); // V4
+        let packet = client.subscribe("t").unwrap();
+        // fixed header(2) + packet id(2) + topic "t"(3) + options(1), no properties byte
+        assert_eq!(packet.len(), 8);
+    }
+
+    #[test]
+    fn incoming_v4_publish_has_no_property_length_to_skip() {
+        let mut client = connected_client(); // V4
+        // PUBLISH, QoS 0, topic "t", payload "hi" - no packet id, no properties
+        let publish = [0x30, 0x05, 0x00, 0x01, 0x74, 0x68, 0x69];
+        let mut received = false;
+        client
+            .receive_packet(&publish, |_, topic, payload, properties| {
+                assert_eq!(topic, "t");
+                assert_eq!(payload, b"hi");
+                assert_eq!(properties, MqttProperties::default());
+                received = true;
+            })
+            .unwrap();
+        assert!(received);
+    }
+
+    #[test]
+    fn properties_round_trip_through_write_and_read() {
+        let mut buf = [0u8; 64];
+        let mut writer = MqttMessageWriter::new(&mut buf);
+        let properties = MqttProperties {
+            payload_format_indicator: Some(1),
+            message_expiry_interval: Some(60),
+            content_type: Some("text/plain"),
+            response_topic: Some("resp/topic"),
+            correlation_data: Some(b"corr"),
+        };
+        properties.write(&mut writer).unwrap();
+        let len = writer.len();
+
+        let mut reader = MqttMessageReader::new(&buf[..len]);
+        assert_eq!(MqttProperties::read(&mut reader).unwrap(), properties);
+    }
+
+    #[test]
+    fn properties_read_skips_trailing_bytes_after_an_unrecognized_property() {
+        let mut buf = [0u8; 32];
+        let mut writer = MqttMessageWriter::new(&mut buf);
+        // property length: 1 (identifier) + 3 (bogus value) = 4
+        writer.write_variable_int(4).unwrap();
+        writer.write_u8(0x05).unwrap(); // not a recognized MqttProperty identifier
+        writer.write_bytes_raw(&[0xAA, 0xBB, 0xCC]).unwrap();
+        let len = writer.len();
+
+        let mut reader = MqttMessageReader::new(&buf[..len]);
+        assert_eq!(
+            MqttProperties::read(&mut reader).unwrap(),
+            MqttProperties::default()
+        );
+        assert_eq!(reader.position(), len); // lands exactly on `end`, not mid-property
+    }
+
+    #[test]
+    fn properties_read_does_not_drop_response_topic_after_subscription_identifier() {
+        let mut buf = [0u8; 32];
+        let mut writer = MqttMessageWriter::new(&mut buf);
+        let subscription_identifier_len = 1 + 1; // identifier byte + 1-byte variable int value
+        let response_topic_len = 1 + 2 + 1; // identifier byte + length-prefixed "r"
+        writer
+            .write_variable_int((subscription_identifier_len + response_topic_len) as u32)
+            .unwrap();
+        writer.write_u8(MqttProperty::SubscriptionIdentifier as u8).unwrap();
+        writer.write_variable_int(5).unwrap();
+        writer.write_u8(MqttProperty::ResponseTopic as u8).unwrap();
+        writer.write_string("r").unwrap();
+        let len = writer.len();
+
+        let mut reader = MqttMessageReader::new(&buf[..len]);
+        let properties = MqttProperties::read(&mut reader).unwrap();
+        assert_eq!(properties.response_topic, Some("r"));
+    }
+
+    #[test]
+    fn properties_read_rejects_a_property_that_overruns_the_declared_length() {
+        let mut buf = [0u8; 32];
+        let mut writer = MqttMessageWriter::new(&mut buf);
+        // Declares only 2 bytes of properties, but ContentType's identifier + length-prefixed
+        // string is longer than that
+        writer.write_variable_int(2).unwrap();
+        writer.write_u8(MqttProperty::ContentType as u8).unwrap();
+        writer.write_string("too long").unwrap();
+        let len = writer.len();
+
+        let mut reader = MqttMessageReader::new(&buf[..len]);
+        assert!(matches!(
+            MqttProperties::read(&mut reader),
+            Err(MqttError::MalformedPacket)
+        ));
+    }
+
+    #[test]
+    fn qos1_publish_tracks_and_clears_pending_ack() {
+        let mut client = connected_client();
+        client
+            .publish_qos("t", b"payload", MqttQoS::AtLeastOnce, MqttProperties::default())
+            .unwrap();
+        assert!(client.pending_qos1.ids.contains(&1));
+
+        // Broker acknowledges packet id 1 with a PUBACK
+        let puback = [0x40, 0x02, 0x00, 0x01];
+        client.receive_packet(&puback, |_, _, _, _| {}).unwrap();
+        assert!(!client.pending_qos1.ids.contains(&1));
+    }
+
+    #[test]
+    fn qos2_publish_runs_full_handshake() {
+        let mut client = connected_client();
+        client
+            .publish_qos("t", b"payload", MqttQoS::ExactlyOnce, MqttProperties::default())
+            .unwrap();
+        assert!(client.pending_qos2_rec.ids.contains(&1));
+
+        // Broker sends PUBREC for packet id 1; the client should move the id from
+        // "awaiting PUBREC" to "awaiting PUBCOMP" and queue a PUBREL in response
+        let pubrec = [0x50, 0x02, 0x00, 0x01];
+        client.receive_packet(&pubrec, |_, _, _, _| {}).unwrap();
+        assert!(!client.pending_qos2_rec.ids.contains(&1));
+        assert!(client.pending_qos2_comp.ids.contains(&1));
+        assert_eq!(client.next_outgoing(), Some(&[0x62, 0x02, 0x00, 0x01][..]));
+
+        // Broker completes the handshake with PUBCOMP
+        let pubcomp = [0x70, 0x02, 0x00, 0x01];
+        client.receive_packet(&pubcomp, |_, _, _, _| {}).unwrap();
+        assert!(!client.pending_qos2_comp.ids.contains(&1));
+    }
+
+    #[test]
+    fn incoming_qos1_publish_queues_a_puback() {
+        let mut client = connected_client();
+        // PUBLISH, QoS 1, topic "t", packet id 7, payload "hi"
+        let publish = [0x32, 0x07, 0x00, 0x01, 0x74, 0x00, 0x07, 0x68, 0x69];
+        let mut received = false;
+        client
+            .receive_packet(&publish, |_, topic, payload, _| {
+                assert_eq!(topic, "t");
+                assert_eq!(payload, b"hi");
+                received = true;
+            })
+            .unwrap();
+        assert!(received);
+        assert_eq!(client.next_outgoing(), Some(&[0x40, 0x02, 0x00, 0x07][..]));
+        assert_eq!(client.next_outgoing(), None);
+    }
+
+    #[test]
+    fn incoming_qos2_publish_completes_with_pubrel_then_pubcomp() {
+        let mut client = connected_client();
+        // PUBLISH, QoS 2, topic "t", packet id 9, payload "hi"
+        let publish = [0x34, 0x07, 0x00, 0x01, 0x74, 0x00, 0x09, 0x68, 0x69];
+        client.receive_packet(&publish, |_, _, _, _| {}).unwrap();
+        assert_eq!(client.next_outgoing(), Some(&[0x50, 0x02, 0x00, 0x09][..]));
+
+        // Broker replies with PUBREL; the client should queue the final PUBCOMP
+        let pubrel = [0x62, 0x02, 0x00, 0x09];
+        client.receive_packet(&pubrel, |_, _, _, _| {}).unwrap();
+        assert_eq!(client.next_outgoing(), Some(&[0x70, 0x02, 0x00, 0x09][..]));
+    }
+
+    #[test]
+    fn feed_reassembles_a_packet_split_across_calls() {
+        let mut client = connected_client();
+        client.ping().unwrap();
+
+        // A PINGRESP (type 13, flags 0, remaining length 0) split byte-by-byte
+        client.feed(&[0xD0], |_, _, _, _| {}).unwrap();
+        assert!(client.ping_pending); // fixed header isn't fully buffered yet
+
+        client.feed(&[0x00], |_, _, _, _| {}).unwrap();
+        assert!(!client.ping_pending); // now complete and dispatched
+    }
+
+    #[test]
+    fn feed_dispatches_every_packet_in_a_coalesced_read() {
+        let mut client = connected_client();
+        client.ping().unwrap();
+
+        // Two PINGRESPs arriving back-to-back in a single read
+        let coalesced = [0xD0, 0x00, 0xD0, 0x00];
+        client.feed(&coalesced, |_, _, _, _| {}).unwrap();
+        assert!(!client.ping_pending);
+    }
+}
@@ -1,5 +1,5 @@
 use super::flags::Flags;
-
+use crate::{MqttError, MqttResult};
 
 pub struct MqttMessageWriter<'a> {
     buffer: &'a mut [u8],
@@ -11,42 +11,55 @@ impl<'a> MqttMessageWriter<'a> {
         Self { buffer, cursor: 0 }
     }
 
-    pub fn write_u8(&mut self, value: u8) {
+    pub fn write_u8(&mut self, value: u8) -> MqttResult<()> {
+        self.reserve(1)?;
         self.buffer[self.cursor] = value;
         self.cursor += 1;
+        Ok(())
     }
 
-    pub fn write_flags(&mut self, value: Flags) {
-        self.write_u8(value.value);
+    pub fn write_flags(&mut self, value: Flags) -> MqttResult<()> {
+        self.write_u8(value.value)
     }
 
-    pub fn write_u16(&mut self, value: u16) {
+    pub fn write_u16(&mut self, value: u16) -> MqttResult<()> {
+        self.reserve(2)?;
         self.buffer[self.cursor] = (value >> 8) as u8;
         self.buffer[self.cursor + 1] = value as u8;
         self.cursor += 2;
+        Ok(())
     }
 
-    pub fn write_string(&mut self, value: &str) {
-        self.write_u16(value.len() as u16);
-        for byte in value.bytes() {
-            self.buffer[self.cursor] = byte;
-            self.cursor += 1;
-        }
+    pub fn write_u32(&mut self, value: u32) -> MqttResult<()> {
+        self.reserve(4)?;
+        self.buffer[self.cursor] = (value >> 24) as u8;
+        self.buffer[self.cursor + 1] = (value >> 16) as u8;
+        self.buffer[self.cursor + 2] = (value >> 8) as u8;
+        self.buffer[self.cursor + 3] = value as u8;
+        self.cursor += 4;
+        Ok(())
     }
 
-    pub fn write_bytes(&mut self, value: &[u8]) {
-        self.write_u16(value.len() as u16);
-        self.write_bytes_raw(value);
+    pub fn write_string(&mut self, value: &str) -> MqttResult<()> {
+        self.write_u16(value.len() as u16)?;
+        self.write_bytes_raw(value.as_bytes())
     }
 
-    pub fn write_bytes_raw(&mut self, value: &[u8]) {
+    pub fn write_bytes(&mut self, value: &[u8]) -> MqttResult<()> {
+        self.write_u16(value.len() as u16)?;
+        self.write_bytes_raw(value)
+    }
+
+    pub fn write_bytes_raw(&mut self, value: &[u8]) -> MqttResult<()> {
+        self.reserve(value.len())?;
         for byte in value {
             self.buffer[self.cursor] = *byte;
             self.cursor += 1;
         }
+        Ok(())
     }
 
-    pub fn write_variable_int(&mut self, mut value: u32) {
+    pub fn write_variable_int(&mut self, mut value: u32) -> MqttResult<()> {
         loop {
             // Successively take the last 7 bits, check if anything remaining (i.e. > 0),
             // and repeat
@@ -55,17 +68,25 @@ impl<'a> MqttMessageWriter<'a> {
             if value > 0 {
                 byte |= 0x80;
             }
-            self.buffer[self.cursor] = byte;
-            self.cursor += 1;
+            self.write_u8(byte)?;
             if value == 0 {
                 break;
             }
         }
+        Ok(())
     }
 
     pub fn len(&self) -> usize {
         self.cursor
     }
+
+    fn reserve(&self, additional: usize) -> MqttResult<()> {
+        if self.cursor + additional > self.buffer.len() {
+            Err(MqttError::BufferFull)
+        } else {
+            Ok(())
+        }
+    }
 }
 
 pub struct MqttMessageReader<'a> {
@@ -76,60 +97,82 @@ pub struct MqttMessageReader<'a> {
 
 impl<'a> MqttMessageReader<'a> {
     pub fn new(buffer: &'a [u8]) -> Self {
-        Self { buffer, cursor: 0, mark: 0 }
+        Self {
+            buffer,
+            cursor: 0,
+            mark: 0,
+        }
     }
 
-    pub fn read_u8(&mut self) -> u8 {
-        let value = self.buffer[self.cursor];
+    pub fn read_u8(&mut self) -> MqttResult<u8> {
+        let value = *self
+            .buffer
+            .get(self.cursor)
+            .ok_or(MqttError::MalformedPacket)?;
         self.cursor += 1;
-        value
+        Ok(value)
     }
 
-    pub fn read_u16(&mut self) -> u16 {
-        let value = ((self.buffer[self.cursor] as u16) << 8) | self.buffer[self.cursor + 1] as u16;
-        self.cursor += 2;
-        value
+    pub fn read_u16(&mut self) -> MqttResult<u16> {
+        let bytes = self.read_bytes_raw(2)?;
+        Ok(((bytes[0] as u16) << 8) | bytes[1] as u16)
     }
 
-    pub fn read_string(&mut self) -> &'a str {
-        let length = self.read_u16() as usize;
-        let start = self.cursor;
-        self.cursor += length;
-        core::str::from_utf8(&self.buffer[start..self.cursor]).unwrap()
+    pub fn read_u32(&mut self) -> MqttResult<u32> {
+        let bytes = self.read_bytes_raw(4)?;
+        Ok(((bytes[0] as u32) << 24)
+            | ((bytes[1] as u32) << 16)
+            | ((bytes[2] as u32) << 8)
+            | bytes[3] as u32)
+    }
+
+    pub fn read_string(&mut self) -> MqttResult<&'a str> {
+        let length = self.read_u16()? as usize;
+        let bytes = self.read_bytes_raw(length)?;
+        core::str::from_utf8(bytes).map_err(|_| MqttError::InvalidUtf8)
     }
 
-    pub fn read_bytes(&mut self) -> &'a [u8] {
-        let length = self.read_u16() as usize;
+    pub fn read_bytes(&mut self) -> MqttResult<&'a [u8]> {
+        let length = self.read_u16()? as usize;
         self.read_bytes_raw(length)
     }
 
-    pub fn read_bytes_raw(&mut self, length: usize) -> &'a [u8] {
+    pub fn read_bytes_raw(&mut self, length: usize) -> MqttResult<&'a [u8]> {
+        if self.cursor + length > self.buffer.len() {
+            return Err(MqttError::MalformedPacket);
+        }
         let start = self.cursor;
         self.cursor += length;
-        &self.buffer[start..self.cursor]
+        Ok(&self.buffer[start..self.cursor])
     }
 
-    pub fn read_variable_int(&mut self) -> u32 {
-        let mut value = 0;
+    /// Reads a variable-length integer, capped at the MQTT spec's four-byte limit
+    /// (a set continuation bit on the fourth byte is rejected), so the maximum value
+    /// is 268435455.
+    pub fn read_variable_int(&mut self) -> MqttResult<u32> {
+        let mut value: u32 = 0;
         let mut shift = 0;
-        loop {
-            let byte = self.buffer[self.cursor];
-            self.cursor += 1;
+        for _ in 0..4 {
+            let byte = self.read_u8()?;
             value |= ((byte & 0x7F) as u32) << shift;
-            shift += 7;
             if byte & 0x80 == 0 {
-                break;
+                return Ok(value);
             }
+            shift += 7;
         }
-        value
+        Err(MqttError::MalformedPacket)
     }
 
     pub fn remaining(&self) -> usize {
-        self.buffer.len() - self.cursor
+        self.buffer.len().saturating_sub(self.cursor)
+    }
+
+    pub fn position(&self) -> usize {
+        self.cursor
     }
 
     pub fn skip(&mut self, length: usize) {
-        self.cursor += length;
+        self.cursor = (self.cursor + length).min(self.buffer.len());
     }
 
     pub fn mark(&mut self) {
@@ -137,10 +180,50 @@ impl<'a> MqttMessageReader<'a> {
     }
 
     pub fn skip_to(&mut self, position: usize) {
-        self.cursor = self.mark + position;
+        self.cursor = (self.mark + position).min(self.buffer.len());
     }
 
     pub fn distance_from_mark(&self) -> usize {
         self.cursor - self.mark
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writer_rejects_writes_past_capacity() {
+        let mut buf = [0u8; 2];
+        let mut writer = MqttMessageWriter::new(&mut buf);
+        writer.write_u8(1).unwrap();
+        writer.write_u8(2).unwrap();
+        assert!(matches!(writer.write_u8(3), Err(MqttError::BufferFull)));
+    }
+
+    #[test]
+    fn reader_rejects_truncated_string() {
+        // Claims a 10-byte string, but only 2 bytes follow
+        let buf = [0x00, 0x0A, b'h', b'i'];
+        let mut reader = MqttMessageReader::new(&buf);
+        assert!(matches!(reader.read_string(), Err(MqttError::MalformedPacket)));
+    }
+
+    #[test]
+    fn reader_rejects_continuation_bit_on_fourth_variable_int_byte() {
+        let buf = [0x80, 0x80, 0x80, 0x80];
+        let mut reader = MqttMessageReader::new(&buf);
+        assert!(matches!(
+            reader.read_variable_int(),
+            Err(MqttError::MalformedPacket)
+        ));
+    }
+
+    #[test]
+    fn reader_accepts_max_variable_int() {
+        // 0xFF, 0xFF, 0xFF, 0x7F encodes the spec-maximum value, 268435455
+        let buf = [0xFF, 0xFF, 0xFF, 0x7F];
+        let mut reader = MqttMessageReader::new(&buf);
+        assert_eq!(reader.read_variable_int().unwrap(), 268435455);
+    }
+}